@@ -118,7 +118,8 @@ fn main() -> ! {
     // erase_flash_storage();
 
     // Setup our TicKV stuff
-    let controller = rp2040_tickv::RP2040FlashCtrl::new(FLASH_END_ADDR, STORAGE_SIZE).unwrap();
+    let controller =
+        rp2040_tickv::RP2040FlashCtrl::new(FLASH_END_ADDR, STORAGE_SIZE, None).unwrap();
     let mut storage_buffer = &mut [0; rp2040_tickv::SECTOR_SIZE];
     let tickv = TicKV::<rp2040_tickv::RP2040FlashCtrl, { rp2040_tickv::SECTOR_SIZE }>::new(
         controller,
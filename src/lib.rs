@@ -8,6 +8,20 @@
 //! You can learn more about TicKV here:
 //! [TicKV](https://docs.tockos.org/tickv/index.html]).
 //!
+//! `RP2040FlashCtrl` also implements the `embedded-storage` crate's
+//! `ReadNorFlash`/`NorFlash`/`MultiwriteNorFlash` traits, so the same reserved flash
+//! region can be handed to anything that speaks `embedded-storage` (other filesystems,
+//! `sequential-storage`, config stores, etc) and not just TicKV.
+//!
+//! Rather than hand-computing `flash_end`/`storage_size` yourself, you can use
+//! [`RP2040FlashCtrl::from_linker`] to derive them from `memory.x` linker symbols so
+//! the storage region can't accidentally overlap your firmware after a rebuild.
+//!
+//! If you know your flash chip's block-erase opcode you can pass it to `new`/
+//! `from_linker` and then call [`RP2040FlashCtrl::erase_range`] after a big
+//! `garbage_collect()` to reclaim a large contiguous span in one go instead of many
+//! single-sector erases.
+//!
 //! For this code to work properly (for now) you'll need to ensure that you have:
 //!
 //! `lto = 'fat'` or `lto = 'thin'`
@@ -43,13 +57,18 @@
 //! your hardware.  So do some testing and make some guesses 👍
 
 #![no_std]
+use core::cell::RefCell;
 use core::slice;
+use embedded_storage::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
 use hal::rom_data;
 use rp2040_hal as hal; // Shortcut
 use tickv::{ErrorCode, FlashController};
 
 pub const BLOCK_SIZE: u32 = 65536; // Larger than flash so block_cmd is ignored
 pub const SECTOR_SIZE: usize = 4096; // 4k blocks are required by RP2040
+pub const PAGE_SIZE: usize = 256; // flash_range_program() requires page-aligned writes
 
 /* IMPORTANT NOTE ABOUT RP2040 FLASH SPACE ADDRESSES:
 When you pass an `addr` to a `rp2040-hal::rom_data` function it wants
@@ -59,15 +78,57 @@ need the address space to start at `0x1000_0000` (aka `FLASH_XIP_BASE`).
 */
 pub const FLASH_XIP_BASE: u32 = 0x1000_0000;
 
+/// Error type for [`RP2040FlashCtrl`]'s `embedded-storage` trait impls.
+///
+/// `FlashController` (TicKV's trait) reports problems via `tickv::ErrorCode` instead;
+/// this is only used by the `ReadNorFlash`/`NorFlash`/`MultiwriteNorFlash` impls below.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The requested offset/length falls outside of `storage_size`.
+    OutOfBounds,
+    /// The requested offset/length isn't aligned to `READ_SIZE`/`WRITE_SIZE`/`ERASE_SIZE`.
+    NotAligned,
+    /// Some other error occurred.
+    Other,
+}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Error::NotAligned => NorFlashErrorKind::NotAligned,
+            Error::Other => NorFlashErrorKind::Other,
+        }
+    }
+}
+
 pub struct RP2040FlashCtrl {
     pub flash_end: u32,     // e.g. 0x0020_0000
     pub storage_size: u32,  // e.g. 128*4096 (has to be multiple of 4096)
     pub base_addr: u32,     // Calculated from flash_end - storage_size
     pub xip_base_addr: u32, // For doing reads
+    // Scratch page used to read-modify-write sub-page/unaligned writes (see `write_rmw`):
+    scratch: RefCell<[u8; SECTOR_SIZE]>,
+    /// When `true`, every write/erase is read back via XIP afterwards and checked
+    /// against the intended result (all-`0xFF` for erases), returning `WriteFail`/
+    /// `EraseFail` on a mismatch instead of trusting the ROM call blindly. Off by
+    /// default since it roughly doubles the time each operation takes; flip it on for
+    /// extra confidence on flash parts you don't fully trust.
+    pub verify: bool,
+    /// Chip-specific block-erase opcode/size (e.g. `(0x52, 32768)` or `(0xD8, 65536)`)
+    /// to use in [`RP2040FlashCtrl::erase_range`] when a requested range is aligned to
+    /// and an exact multiple of that block size, or `None` to always erase one `SECTOR_SIZE` sector at a time.
+    /// Leave this at the conservative `None` default unless you know your flash part
+    /// supports the opcode you're passing.
+    pub block_erase: Option<(u8, u32)>,
 }
 
 impl RP2040FlashCtrl {
-    pub fn new(flash_end: u32, storage_size: u32) -> Result<Self, ErrorCode> {
+    pub fn new(
+        flash_end: u32,
+        storage_size: u32,
+        block_erase: Option<(u8, u32)>,
+    ) -> Result<Self, ErrorCode> {
         if storage_size % SECTOR_SIZE as u32 != 0 {
             // Must be multiple of 4096
             Err(ErrorCode::BufferTooSmall(SECTOR_SIZE))
@@ -79,30 +140,104 @@ impl RP2040FlashCtrl {
                 storage_size,
                 base_addr,
                 xip_base_addr,
+                scratch: RefCell::new([0; SECTOR_SIZE]),
+                verify: false,
+                block_erase,
             })
         }
     }
+
+    /// Like [`RP2040FlashCtrl::new`], but computes `flash_end`/`storage_size` from the
+    /// `_tickv_start`/`_tickv_end` symbols your linker script defines instead of you
+    /// hand-computing (and hardcoding) them yourself.
+    ///
+    /// Every time your firmware image grows or shrinks, the first free byte of flash
+    /// moves with it, so a hardcoded `flash_end`/`storage_size` pair can silently start
+    /// overlapping the program after a rebuild. Deriving the storage window from the
+    /// linker keeps it pinned above `__flash_binary_end` no matter how the firmware
+    /// image changes size.
+    ///
+    /// Add something like this to your `memory.x` (adjust `LENGTH` to match how much
+    /// flash you want to reserve, and make sure it comes after your `FLASH` region):
+    ///
+    /// ```text
+    /// MEMORY {
+    ///     FLASH : ORIGIN = 0x10000000, LENGTH = 2048K - 64K
+    ///     TICKV : ORIGIN = 0x10000000 + 2048K - 64K, LENGTH = 64K
+    ///     RAM   : ORIGIN = 0x20000000, LENGTH = 264K
+    /// }
+    ///
+    /// _tickv_start = ORIGIN(TICKV) - 0x10000000;
+    /// _tickv_end = ORIGIN(TICKV) + LENGTH(TICKV) - 0x10000000;
+    /// ```
+    ///
+    /// `flash_size` is the physical size (in bytes) of your flash chip, e.g. from its
+    /// datasheet or `FLASH_SIZE_MBYTES` in `basic_read_write.rs`. There's no portable
+    /// way to read the chip's actual capacity at build time or from the linker (which
+    /// only knows what `memory.x` told it), so this can't be a true build-time
+    /// assertion -- `from_linker` checks it for you at runtime instead, rejecting a
+    /// `memory.x` whose `TICKV` region was mis-sized past the real end of flash.
+    pub fn from_linker(flash_size: u32, block_erase: Option<(u8, u32)>) -> Result<Self, ErrorCode> {
+        // Safety: these are linker-defined symbols, not actual `u32`s; we only ever
+        // take their address, never read through them.
+        let tickv_start = unsafe { &_tickv_start as *const u32 as u32 };
+        let tickv_end = unsafe { &_tickv_end as *const u32 as u32 };
+        // `__flash_binary_end` is an XIP-mapped address; bring it into the same
+        // `0x0000_0000`-based space as `_tickv_start`/`_tickv_end` before comparing.
+        let flash_binary_end =
+            unsafe { &__flash_binary_end as *const u32 as u32 }.saturating_sub(FLASH_XIP_BASE);
+
+        if tickv_start < flash_binary_end {
+            // The storage region as laid out in `memory.x` would overlap the running
+            // firmware image.
+            return Err(ErrorCode::ObjectTooLarge);
+        }
+        if tickv_end > flash_size {
+            // `memory.x` reserves more flash than the chip actually has.
+            return Err(ErrorCode::ObjectTooLarge);
+        }
+        if tickv_start >= tickv_end {
+            // `memory.x` gave the `TICKV` region a zero/negative length, or the
+            // symbols came out reversed; `tickv_end - tickv_start` would underflow.
+            return Err(ErrorCode::ObjectTooLarge);
+        }
+
+        let storage_size = tickv_end - tickv_start;
+        Self::new(tickv_end, storage_size, block_erase)
+    }
 }
 
-impl<'a> FlashController<SECTOR_SIZE> for RP2040FlashCtrl {
-    fn read_region(
-        // Reads don't need to be in RAM
-        &self,
-        region_number: usize,
-        offset: usize,
-        buf: &mut [u8; SECTOR_SIZE],
-    ) -> Result<(), ErrorCode> {
-        let addr = (self.xip_base_addr + ((region_number * SECTOR_SIZE) as u32 + offset as u32))
-            as *mut u8;
+extern "C" {
+    /// First address (in the `0x0000_0000`-based flash address space `rom_data`
+    /// expects) of the region `memory.x` reserves for TicKV storage.
+    static _tickv_start: u32;
+    /// One past the last address (`0x0000_0000`-based) of the region `memory.x`
+    /// reserves for TicKV storage.
+    static _tickv_end: u32;
+    /// Defined by `cortex-m-rt`'s linker script: one past the end of the program
+    /// image, as an XIP-mapped (`0x1000_0000`-based) address. Used only to
+    /// sanity-check that `_tickv_start` doesn't overlap it.
+    static __flash_binary_end: u32;
+}
+
+impl RP2040FlashCtrl {
+    /// Copies `buf.len()` bytes starting at the given XIP-mapped address. Reads don't
+    /// touch the ROM flash functions so they're safe to run from flash (no RAM
+    /// residency needed).
+    fn read_raw(&self, xip_addr: u32, buf: &mut [u8]) {
+        let addr = xip_addr as *mut u8;
         let slice = unsafe { slice::from_raw_parts(addr, buf.len()) };
-        buf.copy_from_slice(&slice);
-        Ok(())
+        buf.copy_from_slice(slice);
     }
 
+    /// Programs `buf` at the given (non-XIP) flash address using the same
+    /// connect/exit-XIP/program/flush/re-enter-XIP sequence every write needs. If
+    /// `self.verify` is set, reads the bytes back via XIP afterwards and returns
+    /// `ErrorCode::WriteFail` if they don't match `buf` -- the ROM call itself has no
+    /// way to report that a write didn't actually land.
     #[inline(never)]
     #[link_section = ".data.ram_func"]
-    fn write(&self, address: usize, buf: &[u8]) -> Result<(), ErrorCode> {
-        let addr = self.base_addr + address as u32;
+    fn write_raw(&self, addr: u32, buf: &[u8]) -> Result<(), ErrorCode> {
         unsafe {
             cortex_m::interrupt::free(|_cs| {
                 rom_data::connect_internal_flash();
@@ -112,22 +247,264 @@ impl<'a> FlashController<SECTOR_SIZE> for RP2040FlashCtrl {
                 rom_data::flash_enter_cmd_xip(); // Start XIP back up
             });
         }
+        if self.verify {
+            let xip_addr = (addr + FLASH_XIP_BASE) as *const u8;
+            let written = unsafe { slice::from_raw_parts(xip_addr, buf.len()) };
+            if written != buf {
+                return Err(ErrorCode::WriteFail);
+            }
+        }
         Ok(())
     }
 
+    /// Erases `len` bytes at the given (non-XIP) flash address, passing `block_size`/
+    /// `block_cmd` through to `flash_range_erase()` so it can use a chip-specific
+    /// block-erase opcode instead of erasing one 4 KiB sector at a time. If
+    /// `self.verify` is set, reads the range back via XIP afterwards and returns
+    /// `ErrorCode::EraseFail` if it isn't all `0xFF`.
     #[inline(never)]
     #[link_section = ".data.ram_func"]
-    fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
-        let addr = self.base_addr + (region_number * SECTOR_SIZE) as u32;
+    fn erase_raw_with(&self, addr: u32, len: usize, block_size: u32, block_cmd: u8) -> Result<(), ErrorCode> {
         unsafe {
             cortex_m::interrupt::free(|_cs| {
                 rom_data::connect_internal_flash();
                 rom_data::flash_exit_xip();
-                rom_data::flash_range_erase(addr, SECTOR_SIZE, BLOCK_SIZE, 0);
+                rom_data::flash_range_erase(addr, len, block_size, block_cmd);
                 rom_data::flash_flush_cache(); // Get the XIP working again
                 rom_data::flash_enter_cmd_xip(); // Start XIP back up
             });
         }
+        if self.verify {
+            let xip_addr = (addr + FLASH_XIP_BASE) as *const u8;
+            let erased = unsafe { slice::from_raw_parts(xip_addr, len) };
+            if erased.iter().any(|&b| b != 0xFF) {
+                return Err(ErrorCode::EraseFail);
+            }
+        }
+        Ok(())
+    }
+
+    /// Erases one `SECTOR_SIZE` sector at the given (non-XIP) flash address.
+    fn erase_raw(&self, addr: u32) -> Result<(), ErrorCode> {
+        self.erase_raw_with(addr, SECTOR_SIZE, BLOCK_SIZE, 0)
+    }
+
+    /// Erases `sector_count` contiguous `SECTOR_SIZE` sectors starting at
+    /// `region_number`. If `self.block_erase` is configured and the requested range is
+    /// aligned to and an exact multiple of one such block, this issues one
+    /// `flash_range_erase()` per block using that block opcode (e.g. `0x52` for 32 KiB,
+    /// `0xD8` for 64 KiB) instead of many slow single-sector erases -- handy after
+    /// `garbage_collect()` frees a large contiguous span, even one spanning several
+    /// blocks. Falls back to erasing one sector at a time otherwise.
+    pub fn erase_range(&self, region_number: usize, sector_count: usize) -> Result<(), ErrorCode> {
+        self.check_erase_range(region_number, sector_count)?;
+        let addr = self.base_addr + (region_number * SECTOR_SIZE) as u32;
+        let len = (sector_count * SECTOR_SIZE) as u32;
+
+        if let Some((block_cmd, block_size)) = self.block_erase {
+            if addr % block_size == 0 && len % block_size == 0 {
+                let mut block_addr = addr;
+                let end_addr = addr + len;
+                while block_addr < end_addr {
+                    self.erase_raw_with(block_addr, block_size as usize, block_size, block_cmd)?;
+                    block_addr += block_size;
+                }
+                return Ok(());
+            }
+        }
+
+        for sector in 0..sector_count {
+            self.erase_raw(addr + (sector * SECTOR_SIZE) as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure a read of `len` bytes starting at `region_number`/`offset` stays
+    /// inside the `storage_size` window. Without this a buggy caller could read the
+    /// program image sitting just below our storage region. Uses checked arithmetic
+    /// since a caller-supplied `offset`/`len` near `usize::MAX` would otherwise wrap
+    /// back under `storage_size` and slip past this check.
+    fn check_read(&self, region_number: usize, offset: usize, len: usize) -> Result<(), ErrorCode> {
+        let end = region_number
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|v| v.checked_add(offset))
+            .and_then(|v| v.checked_add(len))
+            .ok_or(ErrorCode::ObjectTooLarge)?;
+        if end > self.storage_size as usize {
+            return Err(ErrorCode::ObjectTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at `address`, transparently handling the case where `address`
+    /// and/or `buf.len()` aren't `PAGE_SIZE`-aligned (`flash_range_program()` only
+    /// accepts whole, page-aligned transfers). For every affected page -- aligned or
+    /// not -- this reads the current contents back via XIP into `scratch`, overlays
+    /// the new bytes in RAM, and programs the whole page back. Since NOR flash can
+    /// only clear bits (1 -> 0), an overlay that would need to set a bit back to 1
+    /// can't be satisfied by a plain write and is rejected instead of silently
+    /// corrupting neighbouring bits; always going through this overlay-and-check loop
+    /// (rather than a separate fast path for whole-page writes) means that check can't
+    /// be bypassed just because the caller's write happens to be page-aligned.
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    fn write_rmw(&self, address: usize, buf: &[u8]) -> Result<(), ErrorCode> {
+        let end = address
+            .checked_add(buf.len())
+            .ok_or(ErrorCode::ObjectTooLarge)?;
+        if end > self.storage_size as usize {
+            return Err(ErrorCode::ObjectTooLarge);
+        }
+
+        let mut written = 0;
+        while written < buf.len() {
+            let pos = address + written;
+            let page_start = pos - (pos % PAGE_SIZE);
+            let page_offset = pos - page_start;
+            let chunk_len = (PAGE_SIZE - page_offset).min(buf.len() - written);
+
+            let mut page = self.scratch.borrow_mut();
+            self.read_raw(self.xip_base_addr + page_start as u32, &mut page[..PAGE_SIZE]);
+            for i in 0..chunk_len {
+                let old_byte = page[page_offset + i];
+                let new_byte = buf[written + i];
+                if !old_byte & new_byte != 0 {
+                    return Err(ErrorCode::WriteFail);
+                }
+                page[page_offset + i] = new_byte;
+            }
+            self.write_raw(self.base_addr + page_start as u32, &page[..PAGE_SIZE])?;
+
+            written += chunk_len;
+        }
+        Ok(())
+    }
+
+    /// Maps an `ErrorCode` from `write_rmw`/`erase_raw`/our bounds checks onto the
+    /// `embedded-storage` `Error` type used by our `ReadNorFlash`/`NorFlash` impls.
+    fn to_storage_error(e: ErrorCode) -> Error {
+        match e {
+            ErrorCode::ObjectTooLarge => Error::OutOfBounds,
+            ErrorCode::BufferTooSmall(_) => Error::NotAligned,
+            _ => Error::Other,
+        }
+    }
+
+    /// Makes sure `NorFlash::erase`'s `from..to` range stays inside `storage_size` and
+    /// is sector-aligned the way `erase_raw`'s sector-at-a-time loop requires. Without
+    /// this an out-of-bounds `to` would erase straight through into the firmware image
+    /// below `base_addr`, and an unaligned `to` would silently erase a whole extra
+    /// sector beyond what was asked for.
+    fn check_erase_offsets(&self, from: u32, to: u32) -> Result<(), ErrorCode> {
+        if to > self.storage_size {
+            return Err(ErrorCode::ObjectTooLarge);
+        }
+        if from % SECTOR_SIZE as u32 != 0 || to % SECTOR_SIZE as u32 != 0 {
+            return Err(ErrorCode::BufferTooSmall(SECTOR_SIZE));
+        }
+        Ok(())
+    }
+
+    /// Makes sure the sector being erased stays inside `storage_size`. `region_number`
+    /// already guarantees sector alignment since the address is computed as a multiple
+    /// of `SECTOR_SIZE`. Uses checked arithmetic since a huge `region_number` would
+    /// otherwise overflow and wrap back under `storage_size`.
+    fn check_erase(&self, region_number: usize) -> Result<(), ErrorCode> {
+        let end = region_number
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|v| v.checked_add(SECTOR_SIZE))
+            .ok_or(ErrorCode::ObjectTooLarge)?;
+        if end > self.storage_size as usize {
+            return Err(ErrorCode::ObjectTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Makes sure `sector_count` sectors starting at `region_number` stay inside
+    /// `storage_size`. Uses checked arithmetic since a huge `region_number`/
+    /// `sector_count` would otherwise overflow and wrap back under `storage_size`.
+    fn check_erase_range(&self, region_number: usize, sector_count: usize) -> Result<(), ErrorCode> {
+        let end = region_number
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|v| sector_count.checked_mul(SECTOR_SIZE).and_then(|len| v.checked_add(len)))
+            .ok_or(ErrorCode::ObjectTooLarge)?;
+        if end > self.storage_size as usize {
+            return Err(ErrorCode::ObjectTooLarge);
+        }
         Ok(())
     }
 }
+
+impl<'a> FlashController<SECTOR_SIZE> for RP2040FlashCtrl {
+    fn read_region(
+        // Reads don't need to be in RAM
+        &self,
+        region_number: usize,
+        offset: usize,
+        buf: &mut [u8; SECTOR_SIZE],
+    ) -> Result<(), ErrorCode> {
+        self.check_read(region_number, offset, buf.len())?;
+        let xip_addr =
+            self.xip_base_addr + ((region_number * SECTOR_SIZE) as u32 + offset as u32);
+        self.read_raw(xip_addr, buf);
+        Ok(())
+    }
+
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    fn write(&self, address: usize, buf: &[u8]) -> Result<(), ErrorCode> {
+        self.write_rmw(address, buf)
+    }
+
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
+        self.check_erase(region_number)?;
+        let addr = self.base_addr + (region_number * SECTOR_SIZE) as u32;
+        self.erase_raw(addr)
+    }
+}
+
+impl ErrorType for RP2040FlashCtrl {
+    type Error = Error;
+}
+
+impl ReadNorFlash for RP2040FlashCtrl {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_read(0, offset as usize, bytes.len())
+            .map_err(Self::to_storage_error)?;
+        let xip_addr = self.xip_base_addr + offset;
+        self.read_raw(xip_addr, bytes);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.storage_size as usize
+    }
+}
+
+impl NorFlash for RP2040FlashCtrl {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.check_erase_offsets(from, to)
+            .map_err(Self::to_storage_error)?;
+        let mut addr = self.base_addr + from;
+        let end_addr = self.base_addr + to;
+        while addr < end_addr {
+            self.erase_raw(addr).map_err(Self::to_storage_error)?;
+            addr += SECTOR_SIZE as u32;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.write_rmw(offset as usize, bytes)
+            .map_err(Self::to_storage_error)
+    }
+}
+
+impl MultiwriteNorFlash for RP2040FlashCtrl {}